@@ -16,6 +16,19 @@ pub enum Target {
     X64MS
 }
 
+#[derive(Clone, Copy, ValueEnum, Debug, Default)]
+pub enum AeadAlg {
+    #[default]
+    #[value(name = "xchacha20")]
+    XChaCha20,
+
+    #[value(name = "aes256gcm")]
+    Aes256Gcm,
+
+    #[value(name = "xoodyak")]
+    Xoodyak,
+}
+
 #[derive(Clone, Copy, ValueEnum, Debug)]
 pub enum Mode {
     #[value(name = "encrypt")]
@@ -92,8 +105,24 @@ pub struct Args {
         long,
         num_args(1)
     )]
-    /// path to encryption key file
-    pub key: PathBuf,
+    /// path to 32-byte raw encryption key file (mutually exclusive with --passphrase/--passphrase-file)
+    pub key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        num_args(1),
+        conflicts_with_all = ["key", "passphrase_file"]
+    )]
+    /// derive the key from this passphrase with Argon2id instead of a raw key file
+    pub passphrase: Option<String>,
+
+    #[arg(
+        long,
+        num_args(1),
+        conflicts_with_all = ["key", "passphrase"]
+    )]
+    /// derive the key from the passphrase read from this file (trailing newline trimmed)
+    pub passphrase_file: Option<PathBuf>,
 
     #[arg(
         long
@@ -101,6 +130,28 @@ pub struct Args {
     /// key ID to use in the file header, expected to match if decrypting
     pub key_id: Option<u16>,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = AeadAlg::default()
+    )]
+    /// AEAD algorithm to encrypt with (decrypt dispatches on the stored id)
+    pub aead: AeadAlg,
+
+    #[arg(
+        long
+    )]
+    /// compress the bytecode with zstd before encrypting
+    pub compress: bool,
+
+    #[arg(
+        long,
+        num_args(0..=1),
+        default_missing_value = "65536"
+    )]
+    /// encrypt in segmented STREAM mode with the given chunk size in bytes (default 65536)
+    pub stream: Option<u32>,
+
     #[arg(
         long,
         num_args(1)
@@ -1,163 +1,693 @@
+use std::borrow::Cow;
 use std::io::Write;
 
-use anyhow::{Context, Result, anyhow, ensure};
+use aes_gcm::Aes256Gcm;
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use argon2::{Algorithm, Argon2, Params, Version};
 use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::generic_array::GenericArray;
 use chacha20poly1305::aead::{Aead, KeyInit, Payload};
-
-type Cipher = XChaCha20Poly1305;
+use xoodyak::XoodyakKeyed;
 
 pub const MAGIC: &[u8; 8] = b"LUAUBYTX";
-pub const LUAUCX_VERSION: u8 = 1;
-pub const AEAD_XCHACHA20: u8 = 1;
-pub const NONCE_LEN: usize = 24;
+pub const LUAUCX_VERSION: u8 = 3;
 pub const TAG_LEN: usize = 16;
 
-pub const HEADER_LEN: usize = MAGIC.len()
-    + (2 * size_of::<u8>())
-    + size_of::<u16>()
-    + (2 * size_of::<u32>())
-    + NONCE_LEN
-    + TAG_LEN;
+/// AEAD algorithm identifiers stored in the `aead_id` header byte. The nonce
+/// size is a function of this id (see [`nonce_len`]); all three share the
+/// 16-byte [`TAG_LEN`].
+pub const AEAD_XCHACHA20: u8 = 1;
+pub const AEAD_AES256GCM: u8 = 2;
+pub const AEAD_XOODYAK: u8 = 3;
+
+/// Set when the 32-byte key was derived from a passphrase with Argon2id and
+/// the salt/cost parameters are stored in the header.
+pub const FLAG_ARGON2ID: u8 = 1 << 0;
+
+/// Set when the payload was zstd-compressed before encryption; the original
+/// uncompressed length is stored as a u32 in the flag-gated header region.
+pub const FLAG_COMPRESSED: u8 = 1 << 1;
+
+pub const SALT_LEN: usize = 16;
+
+/// Set when the payload is split into independently-sealed fixed-size chunks
+/// (STREAM construction). The chunk size is stored as a u32 in the flag-gated
+/// header region and the stored nonce is only the base prefix.
+pub const FLAG_STREAM: u8 = 1 << 2;
+
+/// zstd level used for the optional compression stage.
+const ZSTD_LEVEL: i32 = 19;
+
+/// Upper bound on the capacity pre-reserved from the header's `orig_len` hint, so
+/// a hostile file cannot force a huge allocation before zstd validates anything.
+const MAX_PREALLOC: usize = 16 * 1024 * 1024;
+
+/// Width of the big-endian per-chunk counter appended to the base nonce prefix.
+const STREAM_COUNTER_LEN: usize = size_of::<u32>();
+
+/// High bit of the chunk counter, set on the final chunk as the end-of-stream
+/// marker so truncation and reordering fail authentication.
+const STREAM_LAST_CHUNK: u32 = 1 << 31;
+
+/// Size in bytes of the fixed header up to and including the length fields,
+/// before the algorithm-dependent nonce and the tag.
+const FIXED_HEADER_LEN: usize =
+    MAGIC.len() + (3 * size_of::<u8>()) + size_of::<u16>() + (2 * size_of::<u32>());
+
+/// Width of the header-length field (v3+), which follows the authenticated
+/// core and lets forward-compatible readers locate the payload.
+const HEADER_LEN_FIELD: usize = size_of::<u16>();
+
+/// Nonce length for a given AEAD id. XChaCha20 uses a 24-byte nonce, AES-256-GCM
+/// the standard 12-byte nonce, and Xoodyak a 16-byte nonce.
+pub fn nonce_len(aead_id: u8) -> Result<usize> {
+    Ok(match aead_id {
+        AEAD_XCHACHA20 => 24,
+        AEAD_AES256GCM => 12,
+        AEAD_XOODYAK => 16,
+        _ => bail!("unsupported aead id {}", aead_id),
+    })
+}
+
+/// Base fixed header size for `aead_id`, i.e. everything except the flag-gated
+/// fields (Argon2, orig_len, chunk_size) and the ciphertext/ad payload, plus one
+/// tag. Used as a capacity hint.
+pub fn header_len(aead_id: u8) -> Result<usize> {
+    Ok(FIXED_HEADER_LEN + HEADER_LEN_FIELD + nonce_len(aead_id)? + TAG_LEN)
+}
+
+/// Non-secret Argon2id inputs stored verbatim in the header so the same
+/// passphrase deterministically reproduces the key on decrypt.
+#[derive(Clone, Copy, Debug)]
+pub struct Argon2Params {
+    pub salt: [u8; SALT_LEN],
+    pub mem_kib: u32,
+    pub iters: u32,
+    pub parallelism: u8,
+}
+
+impl Argon2Params {
+    /// OWASP second-recommended Argon2id profile (19 MiB, t=2, p=1) with a
+    /// freshly generated random salt.
+    fn with_random_salt() -> Self {
+        let mut salt = [0; SALT_LEN];
+        rand::fill(&mut salt);
+        Self {
+            salt,
+            mem_kib: 19456,
+            iters: 2,
+            parallelism: 1,
+        }
+    }
+
+    fn derive(&self, passphrase: &[u8]) -> Result<[u8; 32]> {
+        let params = Params::new(self.mem_kib, self.iters, self.parallelism as u32, Some(32))
+            .map_err(|e| anyhow!(e))
+            .context("invalid Argon2 parameters")?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let mut key = [0; 32];
+        argon2
+            .hash_password_into(passphrase, &self.salt, &mut key)
+            .map_err(|e| anyhow!(e))
+            .context("key derivation failed")?;
+        Ok(key)
+    }
+}
+
+/// Where the 32-byte AEAD key comes from: either supplied raw or derived from
+/// a passphrase via Argon2id with parameters carried in the header.
+#[derive(Clone, Copy)]
+pub enum Key<'a> {
+    Bytes(&'a [u8]),
+    Passphrase(&'a str),
+}
+
+/// Seal `msg` with the cipher selected by `aead_id`, returning `ciphertext || tag`.
+fn seal(aead_id: u8, key: &[u8; 32], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let payload = Payload { msg, aad };
+    let key = GenericArray::from_slice(key);
+    let out = match aead_id {
+        AEAD_XCHACHA20 => XChaCha20Poly1305::new(key)
+            .encrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|e| anyhow!(e))?,
+        AEAD_AES256GCM => Aes256Gcm::new(key)
+            .encrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|e| anyhow!(e))?,
+        AEAD_XOODYAK => XoodyakKeyed::keyed(key, None, None, None)
+            .map_err(|e| anyhow!(e))?
+            .aead_encrypt_to_vec(Some(nonce), Some(aad), msg)
+            .map_err(|e| anyhow!(e))?,
+        _ => bail!("unsupported aead id {}", aead_id),
+    };
+    Ok(out)
+}
+
+/// Open `ct_and_tag` with the cipher selected by `aead_id`, returning the plaintext.
+fn open(aead_id: u8, key: &[u8; 32], nonce: &[u8], ct_and_tag: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let payload = Payload {
+        msg: ct_and_tag,
+        aad,
+    };
+    let key = GenericArray::from_slice(key);
+    let out = match aead_id {
+        AEAD_XCHACHA20 => XChaCha20Poly1305::new(key)
+            .decrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|e| anyhow!(e))?,
+        AEAD_AES256GCM => Aes256Gcm::new(key)
+            .decrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|e| anyhow!(e))?,
+        AEAD_XOODYAK => XoodyakKeyed::keyed(key, None, None, None)
+            .map_err(|e| anyhow!(e))?
+            .aead_decrypt_to_vec(Some(nonce), Some(aad), ct_and_tag)
+            .map_err(|e| anyhow!(e))?,
+        _ => bail!("unsupported aead id {}", aead_id),
+    };
+    Ok(out)
+}
+
+/// AAD fed to the cipher: the serialized fixed header followed by the caller's
+/// own associated data, in that order.
+fn combined_aad(header: &[u8], user_ad: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(header.len() + user_ad.len());
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(user_ad);
+    aad
+}
+
+/// Bounds-checked little-endian cursor over the header bytes. Every accessor
+/// returns an `anyhow` error instead of panicking when the input runs short.
+struct Reader<'a> {
+    buf: &'a [u8],
+    off: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, off: 0 }
+    }
+
+    fn offset(&self) -> usize {
+        self.off
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.off.checked_add(len).context("header length overflow")?;
+        ensure!(end <= self.buf.len(), "unexpected end of header");
+        let slice = &self.buf[self.off..end];
+        self.off = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
 
-fn read_bytes<'a>(input: &'a [u8], off: &mut usize, len: usize) -> &'a [u8] {
-    let start = *off;
-    *off += len;
-    &input[start..*off]
+    /// Advance to an absolute offset, used to skip header fields a newer writer
+    /// may have appended that this reader does not understand.
+    fn skip_to(&mut self, off: usize) -> Result<()> {
+        ensure!(
+            off >= self.off && off <= self.buf.len(),
+            "invalid header length"
+        );
+        self.off = off;
+        Ok(())
+    }
 }
 
-fn read_u8(input: &[u8], off: &mut usize) -> u8 {
-    let v = input[*off];
-    *off += 1;
-    v
+/// Typed representation of the fixed header. Optional fields are present only
+/// when the corresponding flag bit is set; `nonce` holds the full nonce in
+/// whole-buffer mode and the base prefix in streaming mode.
+pub struct Header {
+    pub version: u8,
+    pub flags: u8,
+    pub aead_id: u8,
+    pub key_id: u16,
+    pub ad_len: u32,
+    /// Ciphertext length (without tag) in whole-buffer mode, or the total size
+    /// of all chunk ciphertexts and tags in streaming mode.
+    pub length_field: u32,
+    pub argon2: Option<Argon2Params>,
+    pub orig_len: Option<u32>,
+    pub chunk_size: Option<u32>,
+    pub nonce: Vec<u8>,
 }
 
-fn read_u16(input: &[u8], off: &mut usize) -> u16 {
-    let start = *off;
-    *off += 2;
-    u16::from_le_bytes(input[start..*off].try_into().unwrap())
+impl Header {
+    /// Serialize the header, returning the bytes. The first [`FIXED_HEADER_LEN`]
+    /// bytes (magic through the length fields) are the authenticated core.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut opt = Vec::new();
+        if let Some(p) = self.argon2 {
+            opt.extend_from_slice(&p.salt);
+            opt.extend_from_slice(&p.mem_kib.to_le_bytes());
+            opt.extend_from_slice(&p.iters.to_le_bytes());
+            opt.push(p.parallelism);
+        }
+        if let Some(orig_len) = self.orig_len {
+            opt.extend_from_slice(&orig_len.to_le_bytes());
+        }
+        if let Some(chunk_size) = self.chunk_size {
+            opt.extend_from_slice(&chunk_size.to_le_bytes());
+        }
+
+        let header_len = FIXED_HEADER_LEN + HEADER_LEN_FIELD + opt.len() + self.nonce.len();
+        let mut out = Vec::with_capacity(header_len);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&[LUAUCX_VERSION, self.flags, self.aead_id]);
+        out.extend_from_slice(&self.key_id.to_le_bytes());
+        out.extend_from_slice(&self.ad_len.to_le_bytes());
+        out.extend_from_slice(&self.length_field.to_le_bytes());
+        out.extend_from_slice(&(header_len as u16).to_le_bytes());
+        out.extend_from_slice(&opt);
+        out.extend_from_slice(&self.nonce);
+        out
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> Result<()> {
+        out.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Decode a header from the start of `blob`, returning the parsed header and
+    /// the offset at which the encrypted payload begins. Bounds are checked, so
+    /// a truncated file yields an error rather than a panic. A newer-than-known
+    /// version is tolerated: the stored header length locates the payload and
+    /// any trailing fields this reader does not understand are skipped.
+    pub fn read(blob: &[u8]) -> Result<(Header, usize)> {
+        let mut r = Reader::new(blob);
+        ensure!(r.take(MAGIC.len())? == MAGIC, "invalid bytecode");
+
+        let version = r.u8()?;
+        // The flags byte was introduced in v2 and the header length in v3.
+        let flags = if version >= 2 { r.u8()? } else { 0 };
+        let aead_id = r.u8()?;
+        let key_id = r.u16()?;
+        let ad_len = r.u32()?;
+        let length_field = r.u32()?;
+        let header_len = if version >= 3 {
+            Some(r.u16()? as usize)
+        } else {
+            None
+        };
+
+        let argon2 = if flags & FLAG_ARGON2ID != 0 {
+            let salt = r.take(SALT_LEN)?.try_into().unwrap();
+            let mem_kib = r.u32()?;
+            let iters = r.u32()?;
+            let parallelism = r.u8()?;
+            Some(Argon2Params {
+                salt,
+                mem_kib,
+                iters,
+                parallelism,
+            })
+        } else {
+            None
+        };
+        let orig_len = if flags & FLAG_COMPRESSED != 0 {
+            Some(r.u32()?)
+        } else {
+            None
+        };
+        let chunk_size = if flags & FLAG_STREAM != 0 {
+            Some(r.u32()?)
+        } else {
+            None
+        };
+
+        let full_nonce_len = nonce_len(aead_id)?;
+        let stored_nonce_len = if chunk_size.is_some() {
+            ensure!(
+                full_nonce_len > STREAM_COUNTER_LEN,
+                "aead nonce is too small for streaming"
+            );
+            full_nonce_len - STREAM_COUNTER_LEN
+        } else {
+            full_nonce_len
+        };
+        let nonce = r.take(stored_nonce_len)?.to_vec();
+
+        let payload_off = match header_len {
+            Some(header_len) => {
+                r.skip_to(header_len)?;
+                header_len
+            }
+            None => r.offset(),
+        };
+
+        Ok((
+            Header {
+                version,
+                flags,
+                aead_id,
+                key_id,
+                ad_len,
+                length_field,
+                argon2,
+                orig_len,
+                chunk_size,
+                nonce,
+            },
+            payload_off,
+        ))
+    }
 }
 
-fn read_u32(input: &[u8], off: &mut usize) -> u32 {
-    let start = *off;
-    *off += 4;
-    u32::from_le_bytes(input[start..*off].try_into().unwrap())
+fn random_nonce(aead_id: u8) -> Result<Vec<u8>> {
+    let mut nonce = vec![0; nonce_len(aead_id)?];
+    rand::fill(nonce.as_mut_slice());
+    Ok(nonce)
 }
 
-fn random_nonce() -> [u8; NONCE_LEN] {
-    let mut nonce = [0; NONCE_LEN];
-    rand::fill(&mut nonce);
+/// Build the per-chunk nonce: the random base prefix followed by the big-endian
+/// chunk counter, with the high bit set on the final chunk.
+fn stream_nonce(prefix: &[u8], counter: u32, last: bool) -> Vec<u8> {
+    let counter = if last {
+        counter | STREAM_LAST_CHUNK
+    } else {
+        counter
+    };
+    let mut nonce = Vec::with_capacity(prefix.len() + STREAM_COUNTER_LEN);
+    nonce.extend_from_slice(prefix);
+    nonce.extend_from_slice(&counter.to_be_bytes());
     nonce
 }
 
+/// Resolve a [`Key`] to the raw 32-byte key used by the cipher, generating and
+/// returning the Argon2id parameters that must be stored in the header when a
+/// passphrase was used.
+fn resolve_encrypt_key(key: Key) -> Result<([u8; 32], u8, Option<Argon2Params>)> {
+    match key {
+        Key::Bytes(k) => {
+            ensure!(k.len() == 32, "key must be 32 bytes");
+            let mut buf = [0; 32];
+            buf.copy_from_slice(k);
+            Ok((buf, 0, None))
+        }
+        Key::Passphrase(p) => {
+            let params = Argon2Params::with_random_salt();
+            let key = params.derive(p.as_bytes())?;
+            Ok((key, FLAG_ARGON2ID, Some(params)))
+        }
+    }
+}
+
+/// Encrypt `bytecode` into `out_buf`, optionally compressing and/or splitting the
+/// payload into STREAM chunks.
+///
+/// Note: unlike the decrypt path, which opens stream chunks one at a time, the encrypt path still
+/// materializes the whole (compressed) payload in memory and the on-disk length
+/// field remains a `u32`. Oversized payloads are rejected up front rather than
+/// silently truncated. Lifting this cap would require a streaming source and a
+/// wider length field; it is an intentional limitation of the current format.
 pub fn encrypt_bytecode_into(
     bytecode: &[u8],
-    nonce: Option<[u8; NONCE_LEN]>,
-    key: &[u8],
+    aead_id: u8,
+    nonce: Option<&[u8]>,
+    key: Key,
     key_id: u16,
     ad: &[u8],
+    compress: bool,
+    stream: Option<u32>,
     out_buf: &mut dyn Write,
 ) -> Result<usize> {
-    ensure!(key.len() == 32, "key must be 32 bytes");
+    let (key, mut flags, argon2) = resolve_encrypt_key(key)?;
+    let nonce_len = nonce_len(aead_id)?;
 
-    let nonce = &nonce.unwrap_or_else(random_nonce);
+    // Compress inside the authenticated payload so tampering is still caught
+    // by the AEAD tag.
+    let payload: Cow<[u8]> = if compress {
+        flags |= FLAG_COMPRESSED;
+        Cow::Owned(zstd::encode_all(bytecode, ZSTD_LEVEL).context("compression failed")?)
+    } else {
+        Cow::Borrowed(bytecode)
+    };
+
+    // `stored_nonce` is the full nonce in whole-buffer mode and only the base
+    // prefix in streaming mode; `length_field` locates the user AD that follows
+    // the payload (see [`Header::length_field`]).
+    let (stored_nonce, chunk_size, length_field) = match stream {
+        Some(chunk_size) => {
+            ensure!(chunk_size > 0, "chunk size must be non-zero");
+            ensure!(
+                nonce_len > STREAM_COUNTER_LEN,
+                "aead nonce is too small for streaming"
+            );
+            flags |= FLAG_STREAM;
+            let chunk_size = chunk_size as usize;
+            let mut prefix = vec![0; nonce_len - STREAM_COUNTER_LEN];
+            rand::fill(prefix.as_mut_slice());
+            let total = payload.len() + chunk_count(payload.len(), chunk_size) * TAG_LEN;
+            (prefix, Some(chunk_size), total)
+        }
+        None => {
+            let nonce = match nonce {
+                Some(nonce) => {
+                    ensure!(
+                        nonce.len() == nonce_len,
+                        "nonce must be {} bytes for this aead",
+                        nonce_len
+                    );
+                    nonce.to_vec()
+                }
+                None => random_nonce(aead_id)?,
+            };
+            (nonce, None, payload.len())
+        }
+    };
+
+    // The on-disk length field is a u32; refuse oversized payloads up front
+    // rather than silently truncating the cast below. This is the intentional
+    // format cap (see [`Header::length_field`]).
+    ensure!(
+        length_field <= u32::MAX as usize,
+        "payload too large for the u32 length field"
+    );
+
+    let header = Header {
+        version: LUAUCX_VERSION,
+        flags,
+        aead_id,
+        key_id,
+        ad_len: ad.len() as u32,
+        length_field: length_field as u32,
+        argon2,
+        orig_len: if compress { Some(bytecode.len() as u32) } else { None },
+        chunk_size: chunk_size.map(|c| c as u32),
+        nonce: stored_nonce.clone(),
+    };
+
+    // The entire serialized header (magic through the nonce) is prepended to the
+    // user AAD, so the tag covers every declared field — version, flags, aead_id,
+    // key_id, the length fields, and the flag-gated Argon2/orig_len/chunk_size
+    // region — and flipping any of them fails authentication.
+    let header_bytes = header.to_bytes();
+    let aad = combined_aad(&header_bytes, ad);
+
+    out_buf.write_all(&header_bytes)?;
+
+    match chunk_size {
+        Some(chunk_size) => {
+            let count = chunk_count(payload.len(), chunk_size);
+            for i in 0..count {
+                let start = i * chunk_size;
+                let end = (start + chunk_size).min(payload.len());
+                let nonce = stream_nonce(&stored_nonce, i as u32, i == count - 1);
+                let sealed = seal(aead_id, &key, &nonce, &payload[start..end], &aad)?;
+                out_buf.write_all(&sealed)?;
+            }
+        }
+        None => {
+            let sealed = seal(aead_id, &key, &stored_nonce, &payload, &aad)?;
+            out_buf.write_all(&sealed)?;
+        }
+    }
 
-    let cipher = Cipher::new(key.into());
-    let ciphertext = cipher
-        .encrypt(
-            nonce.into(),
-            Payload {
-                msg: bytecode,
-                aad: ad,
-            },
-        )
-        .map_err(|e| anyhow!(e))
-        .context("encryption failed")?;
-    let ct_len = ciphertext.len() - TAG_LEN;
-    let (ct, tag) = ciphertext.split_at(ct_len);
-
-    out_buf.write_all(MAGIC)?;
-    out_buf.write_all(&[LUAUCX_VERSION, AEAD_XCHACHA20])?;
-    out_buf.write_all(key_id.to_le_bytes().as_ref())?;
-    out_buf.write_all((ad.len() as u32).to_le_bytes().as_ref())?;
-    out_buf.write_all((ct.len() as u32).to_le_bytes().as_ref())?;
-
-    out_buf.write_all(nonce)?;
-    out_buf.write_all(tag)?;
-    out_buf.write_all(ct)?;
     out_buf.write_all(ad)?;
 
-    Ok(HEADER_LEN + ct.len() + ad.len())
+    // In whole-buffer mode the single tag follows the `length_field` ciphertext;
+    // in streaming mode `length_field` already includes every chunk tag.
+    let payload_written = match chunk_size {
+        Some(_) => length_field,
+        None => length_field + TAG_LEN,
+    };
+    Ok(header_bytes.len() + payload_written + ad.len())
+}
+
+/// Number of STREAM chunks for a payload of `len` bytes, at least one so even an
+/// empty payload carries a terminating chunk.
+fn chunk_count(len: usize, chunk_size: usize) -> usize {
+    len.div_ceil(chunk_size).max(1)
 }
 
 pub fn decrypt_bytecode_into(
     blob: &[u8],
-    key: &[u8],
+    key: Key,
     expected_key_id: Option<u16>,
     out_buf: &mut dyn Write,
     ad_buf: Option<&mut dyn Write>,
 ) -> Result<(usize, Option<usize>)> {
-    ensure!(key.len() == 32, "key must be 32 bytes");
-
-    let mut off = 0;
-
-    let magic = read_bytes(blob, &mut off, MAGIC.len());
-    ensure!(magic == MAGIC, "invalid bytecode");
+    let (header, payload_off) = Header::read(blob)?;
 
-    let ver = read_u8(blob, &mut off);
-    ensure!(ver == LUAUCX_VERSION, "unsupported version {}", ver);
-
-    let aead_id = read_u8(blob, &mut off);
-    ensure!(aead_id == AEAD_XCHACHA20, "unsupported aead id {}", aead_id);
-
-    let key_id = read_u16(blob, &mut off);
     if let Some(expected) = expected_key_id {
         ensure!(
-            expected == key_id,
+            expected == header.key_id,
             "key ID mismatch (expected {}, got {})",
             expected,
-            key_id
+            header.key_id
         );
     }
 
-    let ad_len = read_u32(blob, &mut off) as usize;
-    let ct_len = read_u32(blob, &mut off) as usize;
+    let aead_id = header.aead_id;
+    // In streaming mode the length field already accounts for every chunk tag;
+    // in whole-buffer mode it is the ciphertext length and the single tag
+    // follows it.
+    let ct_len = header.length_field as usize;
+    let payload_len = match header.chunk_size {
+        Some(_) => ct_len,
+        None => ct_len + TAG_LEN,
+    };
 
-    let nonce: &[u8; NONCE_LEN] = read_bytes(blob, &mut off, NONCE_LEN).try_into().unwrap();
-    let tag: &[u8; TAG_LEN] = read_bytes(blob, &mut off, TAG_LEN).try_into().unwrap();
-    let ct = read_bytes(blob, &mut off, ct_len);
-    let ad = read_bytes(blob, &mut off, ad_len);
+    // The entire serialized header (magic through the nonce) is fed back as AAD,
+    // matching the encrypt side, so tampering with any declared field — including
+    // the flag-gated orig_len/chunk_size — fails authentication. v1 predates
+    // header authentication, so only the user AAD is used.
+    let header_core: &[u8] = if header.version >= 2 {
+        &blob[..payload_off]
+    } else {
+        &[]
+    };
 
-    // println!("ad: {:?}", ad);
-    // println!("nonce: {:?}", nonce);
-    // println!("ct: {:?}", ct);
-    // println!("tag: {:?}", tag);
+    // Salt and cost parameters are non-secret and were read out of the header
+    // before the key is re-derived.
+    let key = if let Some(params) = header.argon2 {
+        let Key::Passphrase(passphrase) = key else {
+            return Err(anyhow!("file requires a passphrase to decrypt"));
+        };
+        params.derive(passphrase.as_bytes())?
+    } else {
+        let Key::Bytes(k) = key else {
+            return Err(anyhow!("file requires a raw key to decrypt"));
+        };
+        ensure!(k.len() == 32, "key must be 32 bytes");
+        let mut buf = [0; 32];
+        buf.copy_from_slice(k);
+        buf
+    };
 
-    let mut ct_and_tag = Vec::with_capacity(ct_len + TAG_LEN);
-    ct_and_tag.extend_from_slice(ct);
-    ct_and_tag.extend_from_slice(tag);
+    // The user AD is always the trailing `ad_len` bytes of the blob, so it can
+    // be recovered before the payload is consumed and fed back as AAD.
+    let ad_len = header.ad_len as usize;
+    // The payload and trailing AD must account for the blob exactly; a short blob
+    // is truncated and a long one has junk spliced in, both of which are rejected
+    // rather than silently ignored.
+    let expected_len = payload_off
+        .checked_add(payload_len)
+        .and_then(|n| n.checked_add(ad_len))
+        .context("header declares an implausibly large payload")?;
+    ensure!(blob.len() == expected_len, "malformed file: length mismatch");
+    let ad = &blob[blob.len() - ad_len..];
+    let aad = combined_aad(header_core, ad);
 
-    let cipher = Cipher::new(key.into());
-
-    let pt = cipher
-        .decrypt(
-            nonce.into(),
-            Payload {
-                msg: &ct_and_tag,
-                aad: ad,
-            },
-        )
-        .map_err(|e| anyhow!(e))
-        .context("decryption failed")?;
+    let payload = &blob[payload_off..payload_off + payload_len];
 
+    // The user AD does not depend on the decrypted payload, so write it first.
     let mut ad_written = None;
     if let Some(buf) = ad_buf {
         buf.write_all(ad)
             .context("failed to write additional data")?;
-        ad_written = Some(ad.len())
+        ad_written = Some(ad.len());
+    }
+
+    // zstd needs the whole plaintext before it can inflate, so compressed payloads
+    // must be buffered; everything else is written straight through `out_buf`.
+    let compressed = header.orig_len.is_some();
+    let orig_len = header.orig_len.map(|l| l as usize);
+    let mut buffered: Vec<u8> = Vec::new();
+    let mut direct_len = 0usize;
+    let mut emit = |pt: &[u8]| -> Result<()> {
+        if compressed {
+            buffered.extend_from_slice(pt);
+        } else {
+            out_buf.write_all(pt).context("failed to write plaintext")?;
+            direct_len += pt.len();
+        }
+        Ok(())
+    };
+
+    match header.chunk_size {
+        Some(chunk_size) => {
+            // Each chunk is opened and emitted before the next is read, so an
+            // uncompressed payload holds at most one chunk of plaintext at a time
+            // (compressed payloads are buffered whole, see above), and the
+            // end-of-stream marker must appear exactly on the final chunk.
+            let chunk_size = chunk_size as usize;
+            let mut reader = Reader::new(payload);
+            let mut remaining = ct_len;
+            let mut counter: u32 = 0;
+            loop {
+                let this = (chunk_size + TAG_LEN).min(remaining);
+                ensure!(this >= TAG_LEN, "truncated stream chunk");
+                let last = this == remaining;
+                let chunk = reader.take(this)?;
+                let nonce = stream_nonce(&header.nonce, counter, last);
+                let pt = open(aead_id, &key, &nonce, chunk, &aad).context("decryption failed")?;
+                emit(&pt)?;
+                if last {
+                    break;
+                }
+                counter = counter.checked_add(1).context("too many stream chunks")?;
+                remaining -= this;
+            }
+        }
+        None => {
+            // v1 files stored the payload as `tag || ct`; every later version and
+            // the RustCrypto AEADs expect `ct || tag`, so swap the two halves back
+            // before opening. This preserves the "v1 files still decrypt" invariant.
+            let reordered;
+            let payload = if header.version == 1 {
+                ensure!(payload.len() >= TAG_LEN, "truncated file");
+                let (tag, ct) = payload.split_at(TAG_LEN);
+                reordered = [ct, tag].concat();
+                reordered.as_slice()
+            } else {
+                payload
+            };
+            let pt = open(aead_id, &key, &header.nonce, payload, &aad)
+                .context("decryption failed")?;
+            emit(&pt)?;
+        }
     }
 
-    out_buf
-        .write_all(&pt)
-        .context("failed to write plaintext")?;
-    Ok((pt.len(), ad_written))
+    // Authentication succeeded, so the compressed bytes are trustworthy; inflate
+    // back to the original length recorded in the header and write the result.
+    let pt_len = if compressed {
+        // orig_len is authenticated, but still cap the eagerly-reserved capacity
+        // so a corrupt-but-somehow-valid hint can't trigger a huge allocation,
+        // and verify the inflated length matches what the header promised.
+        let orig_len = orig_len.unwrap_or(0);
+        let mut out = Vec::with_capacity(orig_len.min(MAX_PREALLOC));
+        zstd::stream::copy_decode(buffered.as_slice(), &mut out)
+            .context("decompression failed")?;
+        ensure!(
+            out.len() == orig_len,
+            "decompressed length does not match the header"
+        );
+        out_buf
+            .write_all(&out)
+            .context("failed to write plaintext")?;
+        out.len()
+    } else {
+        direct_len
+    };
+
+    Ok((pt_len, ad_written))
 }
@@ -16,12 +16,23 @@ pub use luaucx::*;
 mod cli;
 use cli::*;
 
+fn aead_id(aead: AeadAlg) -> u8 {
+    match aead {
+        AeadAlg::XChaCha20 => AEAD_XCHACHA20,
+        AeadAlg::Aes256Gcm => AEAD_AES256GCM,
+        AeadAlg::Xoodyak => AEAD_XOODYAK,
+    }
+}
+
 fn encrypt(
     data: Vec<(PathBuf, Vec<u8>)>,
     out_dir: PathBuf,
-    key: &[u8],
+    aead_id: u8,
+    key: Key,
     key_id: Option<u16>,
     aad: &[u8],
+    compress: bool,
+    stream: Option<u32>,
 ) {
     for (path, bytecode) in data {
         // SAFETY: file_name will exist since it refers to a file path (perf boost?)
@@ -30,10 +41,13 @@ fn encrypt(
         let mut out_file = File::create(&out_path).expect("failed to create output file");
         encrypt_bytecode_into(
             &bytecode,
+            aead_id,
             None,
             key,
             key_id.unwrap_or(0),
             aad,
+            compress,
+            stream,
             &mut out_file,
         )
         .unwrap_or_else(|e| {
@@ -63,13 +77,32 @@ fn err(msg: impl fmt::Display, kind: ClapErrorKind) -> ! {
 fn main() {
     let args = Args::parse();
 
-    let key = fs::read(&args.key).expect("failed to read key file");
-    if key.len() != 32 {
-        err(
-            "key file must be exactly 32 bytes",
-            ClapErrorKind::InvalidValue,
-        );
-    }
+    // Either a raw 32-byte key file or a passphrase the key is derived from.
+    let raw_key = args.key.as_ref().map(|path| {
+        let key = fs::read(path).expect("failed to read key file");
+        if key.len() != 32 {
+            err(
+                "key file must be exactly 32 bytes",
+                ClapErrorKind::InvalidValue,
+            );
+        }
+        key
+    });
+    let passphrase = args.passphrase.clone().or_else(|| {
+        args.passphrase_file.as_ref().map(|path| {
+            let raw = fs::read_to_string(path).expect("failed to read passphrase file");
+            raw.trim_end_matches(['\r', '\n']).to_owned()
+        })
+    });
+    let key = match (raw_key.as_deref(), passphrase.as_deref()) {
+        (Some(key), None) => Key::Bytes(key),
+        (None, Some(passphrase)) => Key::Passphrase(passphrase),
+        _ => err(
+            "provide exactly one of --key, --passphrase or --passphrase-file",
+            ClapErrorKind::MissingRequiredArgument,
+        ),
+    };
+
     let out_dir = args.out_dir.unwrap_or_else(|| current_dir().unwrap());
     fs::create_dir_all(&out_dir).unwrap_or_else(|e| {
         err(
@@ -105,7 +138,7 @@ fn main() {
             }
             let aad = aad.unwrap_or_else(OsString::new);
             let aad = aad.as_encoded_bytes();
-            encrypt(compiled, out_dir, &key, args.key_id, aad);
+            encrypt(compiled, out_dir, aead_id(args.aead), key, args.key_id, aad, args.compress, args.stream);
         }
         Subcommands::Encrypt { aad, input } => {
             let aad = aad.unwrap_or_else(OsString::new);
@@ -117,7 +150,7 @@ fn main() {
                     (path, bytecode)
                 })
                 .collect();
-            encrypt(compiled, out_dir, &key, args.key_id, aad);
+            encrypt(compiled, out_dir, aead_id(args.aead), key, args.key_id, aad, args.compress, args.stream);
         }
         Subcommands::Decrypt { input } => {
             for path in input {
@@ -128,7 +161,7 @@ fn main() {
                 };
                 let mut out_file = File::create(&out_path).expect("failed to create output file");
                 let (_pt_len, _ad_len) =
-                    decrypt_bytecode_into(&bytecode, &key, args.key_id, &mut out_file, None)
+                    decrypt_bytecode_into(&bytecode, key, args.key_id, &mut out_file, None)
                         .expect("failed to decrypt bytecode");
                 out_file.sync_all().unwrap_or_else(|e| {
                     err(
@@ -213,19 +246,236 @@ mod tests {
         let mut key = DebugOnDrop([0; 32]);
         rand::fill(&mut *key);
 
-        let mut encrypted = Vec::with_capacity(bytecode.len() + HEADER_LEN);
+        let mut encrypted = Vec::with_capacity(bytecode.len() + header_len(AEAD_XCHACHA20)?);
         let (enc_dur, _written_bytes) = bench! {
-            encrypt_bytecode_into(&bytecode, None, &*key, 0, &[], &mut encrypted)?
+            encrypt_bytecode_into(&bytecode, AEAD_XCHACHA20, None, Key::Bytes(&*key), 0, &[], false, None, &mut encrypted)?
         };
         println!("encryption took {:?}", enc_dur);
 
         let mut decrypted = Vec::with_capacity(bytecode.len());
         let (dec_dur, (_read_bytes, _ad_size)) = bench! {
-            decrypt_bytecode_into(&encrypted, &*key, Some(0), &mut decrypted, None)?
+            decrypt_bytecode_into(&encrypted, Key::Bytes(&*key), Some(0), &mut decrypted, None)?
         };
         assert_eq!(bytecode, decrypted);
         println!("decryption took {:?}", dec_dur);
 
         Ok(())
     }
+
+    /// A blob produced by the released v1 tool (layout `nonce || tag || ct`) must
+    /// still decrypt after the format grew flags, dynamic nonces and streaming.
+    #[test]
+    fn v1_backward_compat() -> anyhow::Result<()> {
+        use chacha20poly1305::XChaCha20Poly1305;
+        use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+
+        let key = [7u8; 32];
+        let nonce = [3u8; 24];
+        let bytecode = b"print('hello from v1')";
+        let ad: &[u8] = b"";
+
+        let cipher = XChaCha20Poly1305::new((&key).into());
+        let ct_and_tag = cipher
+            .encrypt((&nonce).into(), Payload { msg: bytecode, aad: ad })
+            .map_err(|e| anyhow::anyhow!(e))?;
+        let (ct, tag) = ct_and_tag.split_at(ct_and_tag.len() - TAG_LEN);
+
+        // Reproduce the v1 on-disk layout verbatim: no flags byte, no header-length
+        // field, and the tag written ahead of the ciphertext.
+        let mut blob = Vec::new();
+        blob.extend_from_slice(MAGIC);
+        blob.extend_from_slice(&[1, AEAD_XCHACHA20]);
+        blob.extend_from_slice(&0u16.to_le_bytes());
+        blob.extend_from_slice(&(ad.len() as u32).to_le_bytes());
+        blob.extend_from_slice(&(ct.len() as u32).to_le_bytes());
+        blob.extend_from_slice(&nonce);
+        blob.extend_from_slice(tag);
+        blob.extend_from_slice(ct);
+        blob.extend_from_slice(ad);
+
+        let mut decrypted = Vec::new();
+        decrypt_bytecode_into(&blob, Key::Bytes(&key), Some(0), &mut decrypted, None)?;
+        assert_eq!(&decrypted, bytecode);
+
+        Ok(())
+    }
+
+    /// Segmented STREAM mode round-trips across several chunks, and both
+    /// truncation of the final chunk and reordering of two chunks are rejected.
+    #[test]
+    fn stream_roundtrip_and_tamper() -> anyhow::Result<()> {
+        let key = [9u8; 32];
+        let bytecode: Vec<u8> = (0..200u32).map(|i| i as u8).collect();
+        let chunk = 64u32;
+
+        let mut enc = Vec::new();
+        encrypt_bytecode_into(
+            &bytecode,
+            AEAD_XCHACHA20,
+            None,
+            Key::Bytes(&key),
+            0,
+            &[],
+            false,
+            Some(chunk),
+            &mut enc,
+        )?;
+
+        let mut dec = Vec::new();
+        decrypt_bytecode_into(&enc, Key::Bytes(&key), Some(0), &mut dec, None)?;
+        assert_eq!(dec, bytecode);
+
+        // Dropping bytes off the final chunk leaves the blob shorter than the
+        // header declares, which must surface as an error rather than a short read.
+        let mut truncated = enc.clone();
+        truncated.truncate(enc.len() - (TAG_LEN + 1));
+        let mut sink = Vec::new();
+        assert!(
+            decrypt_bytecode_into(&truncated, Key::Bytes(&key), Some(0), &mut sink, None).is_err()
+        );
+
+        // Swapping two full chunks breaks the per-chunk nonce counter, so the
+        // AEAD tag no longer validates.
+        let (_, payload_off) = Header::read(&enc)?;
+        let region = chunk as usize + TAG_LEN;
+        let (a, b) = (payload_off, payload_off + region);
+        let first = enc[a..a + region].to_vec();
+        let second = enc[b..b + region].to_vec();
+        let mut reordered = enc.clone();
+        reordered[a..a + region].copy_from_slice(&second);
+        reordered[b..b + region].copy_from_slice(&first);
+        let mut sink = Vec::new();
+        assert!(
+            decrypt_bytecode_into(&reordered, Key::Bytes(&key), Some(0), &mut sink, None).is_err()
+        );
+
+        Ok(())
+    }
+
+    /// The AES-256-GCM and Xoodyak ids each select their own nonce size and
+    /// cipher and still round-trip through the dynamic header layout.
+    #[test]
+    fn aes_and_xoodyak_roundtrip() -> anyhow::Result<()> {
+        let key = [5u8; 32];
+        let bytecode = b"print('pluggable aead')";
+        for aead in [AEAD_AES256GCM, AEAD_XOODYAK] {
+            let mut enc = Vec::new();
+            encrypt_bytecode_into(
+                bytecode,
+                aead,
+                None,
+                Key::Bytes(&key),
+                0,
+                &[],
+                false,
+                None,
+                &mut enc,
+            )?;
+            let mut dec = Vec::new();
+            decrypt_bytecode_into(&enc, Key::Bytes(&key), Some(0), &mut dec, None)?;
+            assert_eq!(&dec, bytecode, "aead id {} did not round-trip", aead);
+        }
+
+        Ok(())
+    }
+
+    /// Tampering with an authenticated header field (here `key_id`) must fail
+    /// the AEAD tag now that the fixed header is fed back as associated data.
+    #[test]
+    fn header_tamper_rejected() -> anyhow::Result<()> {
+        let key = [1u8; 32];
+        let bytecode = b"print('authenticated header')";
+
+        let mut enc = Vec::new();
+        encrypt_bytecode_into(
+            bytecode,
+            AEAD_XCHACHA20,
+            None,
+            Key::Bytes(&key),
+            7,
+            b"context",
+            false,
+            None,
+            &mut enc,
+        )?;
+
+        // The key_id lives in the authenticated core, at MAGIC + version + flags
+        // + aead_id.
+        let key_id_off = MAGIC.len() + 3;
+        let mut tampered = enc.clone();
+        tampered[key_id_off] ^= 0xff;
+        let mut sink = Vec::new();
+        assert!(
+            decrypt_bytecode_into(&tampered, Key::Bytes(&key), None, &mut sink, None).is_err()
+        );
+
+        Ok(())
+    }
+
+    /// A compressed payload shrinks on disk and inflates back to the exact
+    /// original bytecode on decrypt.
+    #[test]
+    fn compressed_roundtrip() -> anyhow::Result<()> {
+        let key = [2u8; 32];
+        // Highly compressible input so the flag path is actually exercised.
+        let bytecode = vec![0xabu8; 4096];
+
+        let mut enc = Vec::new();
+        encrypt_bytecode_into(
+            &bytecode,
+            AEAD_XCHACHA20,
+            None,
+            Key::Bytes(&key),
+            0,
+            &[],
+            true,
+            None,
+            &mut enc,
+        )?;
+        assert!(enc.len() < bytecode.len(), "compression did not shrink payload");
+
+        let mut dec = Vec::new();
+        decrypt_bytecode_into(&enc, Key::Bytes(&key), Some(0), &mut dec, None)?;
+        assert_eq!(dec, bytecode);
+
+        Ok(())
+    }
+
+    /// Short input is rejected with an error at every prefix length instead of
+    /// panicking on an out-of-bounds slice.
+    #[test]
+    fn truncated_header_errors() -> anyhow::Result<()> {
+        let key = [4u8; 32];
+        let bytecode = b"print('bounds checked')";
+
+        let mut enc = Vec::new();
+        encrypt_bytecode_into(
+            bytecode,
+            AEAD_XCHACHA20,
+            None,
+            Key::Bytes(&key),
+            0,
+            &[],
+            false,
+            None,
+            &mut enc,
+        )?;
+
+        for len in 0..enc.len() {
+            let mut sink = Vec::new();
+            assert!(
+                decrypt_bytecode_into(&enc[..len], Key::Bytes(&key), None, &mut sink, None).is_err(),
+                "prefix of {} bytes should not decrypt",
+                len
+            );
+        }
+
+        // Trailing junk spliced after the AD must also be rejected, not ignored.
+        let mut padded = enc.clone();
+        padded.extend_from_slice(b"junk");
+        let mut sink = Vec::new();
+        assert!(decrypt_bytecode_into(&padded, Key::Bytes(&key), None, &mut sink, None).is_err());
+
+        Ok(())
+    }
 }